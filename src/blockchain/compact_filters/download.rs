@@ -0,0 +1,213 @@
+// Magical Bitcoin Library
+// Written in 2020 by
+//     Alekos Filini <alekos.filini@gmail.com>
+//
+// Copyright (c) 2020 Magical Bitcoin
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::collections::BTreeMap;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+
+use bitcoin::hash_types::{BlockHash, TxMerkleNode};
+use bitcoin::{Block, BlockHeader};
+
+use super::pool::PeerPool;
+use super::CompactFiltersError;
+
+/// Maximum number of blocks downloaded in parallel by [`download_block_range`]
+const DOWNLOAD_CONCURRENCY: usize = 8;
+
+/// Maximum number of times a single block is requeued after a peer times out or sends an invalid
+/// response, before [`download_block_range`] gives up on it
+const MAX_BLOCK_RETRIES: u32 = 3;
+
+/// Download a contiguous range of full blocks in parallel using the peers in `pool`
+///
+/// `blocks` lists the `(height, hash)` pairs to fetch, ordered by ascending height. One worker
+/// per available peer (up to [`DOWNLOAD_CONCURRENCY`]) pulls the next block off the queue and
+/// requests it with [`GetData(WitnessBlock)`](bitcoin::network::message_blockdata::Inventory::WitnessBlock).
+/// [`PeerPool::get_block`] already retries a single timed-out or misbehaving peer against the
+/// rest of the pool internally; on top of that, a worker that still gets an error here requeues
+/// the block up to [`MAX_BLOCK_RETRIES`] times so a bad run doesn't busy-loop forever. A
+/// `notfound` that every currently healthy peer agrees on, on the other hand, comes back here as
+/// `Ok(None)` — an authoritative answer, not a transient failure — so that block is not retried
+/// and the whole range fails with [`CompactFiltersError::NoPeers`], the same error returned when
+/// a block runs out of retries. Results are buffered and delivered to `callback` strictly in
+/// ascending height order, regardless of the order in which they actually arrive over the wire.
+pub fn download_block_range<F>(
+    blocks: &[(u32, BlockHash)],
+    pool: &PeerPool,
+    callback: F,
+) -> Result<(), CompactFiltersError>
+where
+    F: Fn(&Block, u32) + Sync,
+{
+    if blocks.is_empty() {
+        return Ok(());
+    }
+
+    let workers = pool.len().clamp(1, DOWNLOAD_CONCURRENCY);
+    let queue = Mutex::new(
+        blocks
+            .iter()
+            .map(|&(height, hash)| (height, hash, 0u32))
+            .collect::<Vec<_>>(),
+    );
+    let (sender, receiver) = mpsc::channel();
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            let queue = &queue;
+            let sender = sender.clone();
+            scope.spawn(move || loop {
+                let (height, hash, attempt) = match queue.lock().unwrap().pop() {
+                    Some(item) => item,
+                    None => break,
+                };
+
+                match pool.get_block(hash) {
+                    Ok(Some(block)) => {
+                        let _ = sender.send(Some((height, block)));
+                    }
+                    Ok(None) => {
+                        // Every currently healthy peer truthfully said they don't have this
+                        // block; that's authoritative, not something retrying would fix.
+                        let _ = sender.send(None);
+                        break;
+                    }
+                    Err(_) if attempt < MAX_BLOCK_RETRIES && !pool.is_empty() => {
+                        queue.lock().unwrap().push((height, hash, attempt + 1));
+                    }
+                    Err(_) => {
+                        let _ = sender.send(None);
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+
+        deliver_in_order(receiver.into_iter(), blocks[0].0, blocks.len(), &callback)
+    })
+}
+
+/// Deliver `results` to `callback` strictly in ascending height order, buffering any block that
+/// arrives before the one `callback` is still waiting on
+///
+/// A `None` anywhere in `results` signals that a worker gave up on its block, so the whole range
+/// failed; pulled out of [`download_block_range`] so the reordering logic can be driven with
+/// synthetic results instead of a real [`PeerPool`].
+fn deliver_in_order(
+    results: impl Iterator<Item = Option<(u32, Block)>>,
+    start_height: u32,
+    total: usize,
+    callback: &(impl Fn(&Block, u32) + Sync),
+) -> Result<(), CompactFiltersError> {
+    let mut buffer = BTreeMap::new();
+    let mut next_to_deliver = start_height;
+    let mut delivered = 0usize;
+    let mut failed = false;
+
+    for item in results {
+        match item {
+            Some((height, block)) => {
+                buffer.insert(height, block);
+                while let Some(block) = buffer.remove(&next_to_deliver) {
+                    callback(&block, next_to_deliver);
+                    delivered += 1;
+                    next_to_deliver += 1;
+                }
+            }
+            None => failed = true,
+        }
+    }
+
+    if failed || delivered != total {
+        Err(CompactFiltersError::NoPeers)
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use bitcoin::hashes::Hash;
+
+    use super::*;
+
+    /// A block whose `nonce` is `seed`, so tests can tell delivered blocks apart
+    fn dummy_block(seed: u32) -> Block {
+        Block {
+            header: BlockHeader {
+                version: 1,
+                prev_blockhash: BlockHash::hash(&[0]),
+                merkle_root: TxMerkleNode::hash(&[0]),
+                time: 0,
+                bits: 0,
+                nonce: seed,
+            },
+            txdata: vec![],
+        }
+    }
+
+    #[test]
+    fn test_deliver_in_order_reassembles_shuffled_results() {
+        let results = vec![
+            Some((12, dummy_block(12))),
+            Some((10, dummy_block(10))),
+            Some((11, dummy_block(11))),
+        ];
+
+        let delivered = RefCell::new(Vec::new());
+        let result = deliver_in_order(results.into_iter(), 10, 3, &|block, height| {
+            delivered.borrow_mut().push((height, block.header.nonce));
+        });
+
+        assert!(result.is_ok());
+        assert_eq!(delivered.into_inner(), vec![(10, 10), (11, 11), (12, 12)]);
+    }
+
+    #[test]
+    fn test_deliver_in_order_fails_on_a_dropped_block() {
+        let results = vec![Some((10, dummy_block(10))), None, Some((12, dummy_block(12)))];
+
+        let delivered = RefCell::new(Vec::new());
+        let result = deliver_in_order(results.into_iter(), 10, 3, &|block, height| {
+            delivered.borrow_mut().push((height, block.header.nonce));
+        });
+
+        assert!(result.is_err());
+        // Height 10 still reaches the callback; height 12 never does, since 11 never arrives to
+        // unblock it.
+        assert_eq!(delivered.into_inner(), vec![(10, 10)]);
+    }
+
+    #[test]
+    fn test_deliver_in_order_fails_when_short_of_total() {
+        let results = vec![Some((10, dummy_block(10))), Some((11, dummy_block(11)))];
+
+        let result = deliver_in_order(results.into_iter(), 10, 3, &|_, _| {});
+
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,378 @@
+// Magical Bitcoin Library
+// Written in 2020 by
+//     Alekos Filini <alekos.filini@gmail.com>
+//
+// Copyright (c) 2020 Magical Bitcoin
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use bitcoin::hash_types::{BlockHash, FilterHash, FilterHeader};
+use bitcoin::hashes::{sha256d, Hash, HashEngine};
+
+use super::pool::PeerPool;
+use super::CompactFiltersError;
+
+/// A `(height, block hash)` pair accepted into, or removed from, the local filter-header chain
+pub type ChainEntry = (u32, BlockHash);
+
+/// A block hash together with the filter header BIP157 says should follow it
+#[derive(Debug, Clone, Copy)]
+struct StoredEntry {
+    block_hash: BlockHash,
+    filter_header: FilterHeader,
+}
+
+/// Outcome of applying a new batch of compact filter headers to a [`FilterHeaderChain`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncOutcome {
+    /// The batch didn't move the local tip: it was empty, or every entry in it was already known.
+    /// A batch that doesn't connect to anything the chain has accepted is an error instead.
+    TipUnchanged,
+    /// The local tip changed, possibly because of a reorg
+    TipChanged {
+        /// Hash of the new tip
+        new_tip: BlockHash,
+        /// Height of the new tip
+        height: u32,
+        /// Blocks that were on the previous best chain but aren't on the new one, in
+        /// descending-height order so a caller can undo them deterministically
+        reverted: Vec<ChainEntry>,
+        /// Blocks on the new best chain that weren't part of the previous one, in
+        /// ascending-height order
+        connected: Vec<ChainEntry>,
+    },
+}
+
+/// Chain the filter header that should follow `filter_hash`, given the filter header of the
+/// block before it, per BIP157: `SHA256D(filter_hash || previous_filter_header)`
+fn chain_filter_header(filter_hash: FilterHash, previous_filter_header: FilterHeader) -> FilterHeader {
+    let mut engine = sha256d::Hash::engine();
+    engine.input(filter_hash.as_ref());
+    engine.input(previous_filter_header.as_ref());
+
+    FilterHeader::from_engine(engine)
+}
+
+/// Tracks the local compact-filter header chain and detects reorgs when extending it
+///
+/// Every accepted entry stores both the block hash it was learned for and the filter header BIP157
+/// chains on top of it, so a later [`cfilter`](bitcoin::network::message_filter::CFilter) for that
+/// block can be authenticated by recomputing its hash and checking it against what's stored here,
+/// rather than trusting whichever peer happens to answer.
+///
+/// Unlike assuming every new batch of headers linearly extends the tip, [`apply_verified_batch`]
+/// is handed an `anchor_height` the caller has already matched against its own last known good
+/// header (see [`sync_cf_headers`]); that anchor, moved up past any blocks the old chain and the
+/// batch share right above it, becomes the common ancestor, and everything above
+/// it on the old chain is reported as [`reverted`](SyncOutcome::TipChanged::reverted) while
+/// everything above it on the new chain is reported as
+/// [`connected`](SyncOutcome::TipChanged::connected). An anchor that isn't already part of the
+/// chain is rejected outright instead of being silently treated as the checkpoint, so a disjoint
+/// or dishonest batch can't wipe out the chain accumulated so far.
+///
+/// [`apply_verified_batch`]: FilterHeaderChain::apply_verified_batch
+#[derive(Debug, Clone)]
+pub struct FilterHeaderChain {
+    /// Height of `chain[0]`
+    base_height: u32,
+    /// Accepted entries, ordered by ascending height with no gaps
+    chain: Vec<StoredEntry>,
+}
+
+impl FilterHeaderChain {
+    /// Create a chain rooted at `checkpoint`, a height/hash pair already known to be final, whose
+    /// filter header is `filter_header`
+    pub fn new(checkpoint: ChainEntry, filter_header: FilterHeader) -> Self {
+        FilterHeaderChain {
+            base_height: checkpoint.0,
+            chain: vec![StoredEntry {
+                block_hash: checkpoint.1,
+                filter_header,
+            }],
+        }
+    }
+
+    /// Height of the current tip
+    pub fn tip_height(&self) -> u32 {
+        self.base_height + self.chain.len() as u32 - 1
+    }
+
+    /// Hash of the current tip
+    pub fn tip_hash(&self) -> BlockHash {
+        self.chain
+            .last()
+            .expect("chain always has at least the checkpoint")
+            .block_hash
+    }
+
+    /// Filter header of the current tip
+    pub fn tip_filter_header(&self) -> FilterHeader {
+        self.chain
+            .last()
+            .expect("chain always has at least the checkpoint")
+            .filter_header
+    }
+
+    fn entry_at(&self, height: u32) -> Option<(BlockHash, FilterHeader)> {
+        height
+            .checked_sub(self.base_height)
+            .and_then(|offset| self.chain.get(offset as usize))
+            .map(|entry| (entry.block_hash, entry.filter_header))
+    }
+
+    /// Apply a batch of `(block hash, filter header)` pairs that have already been verified to
+    /// chain on top of `anchor_height`, in ascending-height order and with no gaps. Returns
+    /// whether doing so changed the local tip and, if so, which blocks were reverted and which
+    /// were connected.
+    ///
+    /// `anchor_height` must already be present in the chain; this is what prevents a batch that
+    /// doesn't actually connect to anything we've accepted from being mistaken for one that
+    /// reorgs all the way back to the checkpoint.
+    ///
+    /// Invariants upheld: a block only ever appears in `connected` once it's part of the final
+    /// best chain, and `reverted` always lists abandoned blocks in descending-height order.
+    fn apply_verified_batch(
+        &mut self,
+        anchor_height: u32,
+        entries: &[(BlockHash, FilterHeader)],
+    ) -> Result<SyncOutcome, CompactFiltersError> {
+        if self.entry_at(anchor_height).is_none() {
+            return Err(CompactFiltersError::InvalidResponse);
+        }
+        if entries.is_empty() {
+            return Ok(SyncOutcome::TipUnchanged);
+        }
+
+        let anchor_offset = (anchor_height - self.base_height) as usize;
+
+        let mut reverted: Vec<ChainEntry> = self.chain[anchor_offset + 1..]
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| (anchor_height + 1 + i as u32, entry.block_hash))
+            .rev()
+            .collect();
+
+        let shared = reverted
+            .iter()
+            .rev()
+            .zip(entries.iter())
+            .take_while(|((_, old_hash), (new_hash, _))| old_hash == new_hash)
+            .count();
+
+        if shared == entries.len() {
+            return Ok(SyncOutcome::TipUnchanged);
+        }
+
+        // Blocks both chains share right above the anchor are neither reverted nor connected
+        reverted.truncate(reverted.len() - shared);
+        let (anchor_height, anchor_offset) = (anchor_height + shared as u32, anchor_offset + shared);
+        let entries = &entries[shared..];
+
+        self.chain.truncate(anchor_offset + 1);
+
+        let connected: Vec<ChainEntry> = entries
+            .iter()
+            .enumerate()
+            .map(|(i, (block_hash, _))| (anchor_height + 1 + i as u32, *block_hash))
+            .collect();
+
+        self.chain
+            .extend(entries.iter().map(|(block_hash, filter_header)| StoredEntry {
+                block_hash: *block_hash,
+                filter_header: *filter_header,
+            }));
+
+        let new_tip = *connected.last().expect("checked non-empty above");
+        Ok(SyncOutcome::TipChanged {
+            new_tip: new_tip.1,
+            height: new_tip.0,
+            reverted,
+            connected,
+        })
+    }
+}
+
+/// Fetch a batch of `cfheaders` from `pool`, verify it against `chain`'s filter header at
+/// `anchor_height`, and apply it
+///
+/// `block_hashes` must list the block hash at every height covered by `[start_height, stop_hash]`
+/// in ascending order, typically sourced from a companion block-header sync, since a `cfheaders`
+/// response only carries filter hashes and a `previous_filter_header` link rather than block
+/// hashes directly. `anchor_height` is the height immediately before `block_hashes[0]` and must
+/// already be stored in `chain`; the response is rejected unless its `previous_filter_header`
+/// matches the filter header `chain` has recorded there, which is what lets a forged batch from a
+/// dishonest peer be told apart from a genuine one instead of being accepted on the strength of
+/// its length alone.
+pub fn sync_cf_headers(
+    chain: &mut FilterHeaderChain,
+    pool: &PeerPool,
+    filter_type: u8,
+    anchor_height: u32,
+    block_hashes: &[ChainEntry],
+) -> Result<SyncOutcome, CompactFiltersError> {
+    let (start_height, _) = match block_hashes.first() {
+        Some(entry) => *entry,
+        None => return Ok(SyncOutcome::TipUnchanged),
+    };
+    let (_, stop_hash) = *block_hashes.last().expect("checked non-empty above");
+
+    let (_, anchor_filter_header) = chain
+        .entry_at(anchor_height)
+        .ok_or(CompactFiltersError::InvalidResponse)?;
+
+    let response = pool.get_cf_headers(filter_type, start_height, stop_hash)?;
+    if response.filter_hashes.len() != block_hashes.len() {
+        return Err(CompactFiltersError::InvalidResponse);
+    }
+    if response.previous_filter_header != anchor_filter_header {
+        return Err(CompactFiltersError::InvalidResponse);
+    }
+
+    let mut previous_filter_header = anchor_filter_header;
+    let mut entries = Vec::with_capacity(block_hashes.len());
+    for (&(_, block_hash), &filter_hash) in block_hashes.iter().zip(response.filter_hashes.iter()) {
+        let filter_header = chain_filter_header(filter_hash, previous_filter_header);
+        entries.push((block_hash, filter_header));
+        previous_filter_header = filter_header;
+    }
+
+    chain.apply_verified_batch(anchor_height, &entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block_hash(seed: u8) -> BlockHash {
+        BlockHash::hash(&[seed])
+    }
+
+    fn filter_header(seed: u8) -> FilterHeader {
+        FilterHeader::hash(&[seed])
+    }
+
+    #[test]
+    fn test_apply_verified_batch_linear_extend() {
+        let mut chain = FilterHeaderChain::new((0, block_hash(0)), filter_header(0));
+
+        let outcome = chain
+            .apply_verified_batch(0, &[(block_hash(1), filter_header(1)), (block_hash(2), filter_header(2))])
+            .unwrap();
+
+        assert_eq!(
+            outcome,
+            SyncOutcome::TipChanged {
+                new_tip: block_hash(2),
+                height: 2,
+                reverted: vec![],
+                connected: vec![(1, block_hash(1)), (2, block_hash(2))],
+            }
+        );
+        assert_eq!(chain.tip_height(), 2);
+        assert_eq!(chain.tip_hash(), block_hash(2));
+        assert_eq!(chain.tip_filter_header(), filter_header(2));
+    }
+
+    #[test]
+    fn test_apply_verified_batch_reorg() {
+        let mut chain = FilterHeaderChain::new((0, block_hash(0)), filter_header(0));
+        chain
+            .apply_verified_batch(0, &[(block_hash(1), filter_header(1)), (block_hash(2), filter_header(2))])
+            .unwrap();
+
+        // A competing batch anchored at the same common ancestor (height 0) replaces both blocks.
+        let outcome = chain
+            .apply_verified_batch(
+                0,
+                &[(block_hash(11), filter_header(11)), (block_hash(12), filter_header(12))],
+            )
+            .unwrap();
+
+        assert_eq!(
+            outcome,
+            SyncOutcome::TipChanged {
+                new_tip: block_hash(12),
+                height: 2,
+                reverted: vec![(2, block_hash(2)), (1, block_hash(1))],
+                connected: vec![(1, block_hash(11)), (2, block_hash(12))],
+            }
+        );
+        assert_eq!(chain.tip_hash(), block_hash(12));
+    }
+
+    #[test]
+    fn test_apply_verified_batch_rejects_unknown_anchor() {
+        let mut chain = FilterHeaderChain::new((0, block_hash(0)), filter_header(0));
+        chain
+            .apply_verified_batch(0, &[(block_hash(1), filter_header(1))])
+            .unwrap();
+
+        let result = chain.apply_verified_batch(5, &[(block_hash(99), filter_header(99))]);
+
+        assert!(matches!(result, Err(CompactFiltersError::InvalidResponse)));
+        // The chain must be untouched by the rejected batch.
+        assert_eq!(chain.tip_height(), 1);
+        assert_eq!(chain.tip_hash(), block_hash(1));
+    }
+
+    #[test]
+    fn test_apply_verified_batch_rejects_anchor_before_checkpoint() {
+        // Chain only goes back to height 100; a batch disjoint from everything we hold (e.g.
+        // anchored at height 50) must be rejected rather than snapped onto the checkpoint and
+        // used to wipe out the accumulated chain above it.
+        let mut chain = FilterHeaderChain::new((100, block_hash(100)), filter_header(100));
+        chain
+            .apply_verified_batch(100, &[(block_hash(101), filter_header(101))])
+            .unwrap();
+
+        let result = chain.apply_verified_batch(50, &[(block_hash(51), filter_header(51))]);
+
+        assert!(matches!(result, Err(CompactFiltersError::InvalidResponse)));
+        assert_eq!(chain.tip_height(), 101);
+        assert_eq!(chain.tip_hash(), block_hash(101));
+    }
+
+    #[test]
+    fn test_apply_verified_batch_identical_tip_is_unchanged() {
+        let mut chain = FilterHeaderChain::new((0, block_hash(0)), filter_header(0));
+        chain
+            .apply_verified_batch(0, &[(block_hash(1), filter_header(1))])
+            .unwrap();
+
+        let outcome = chain
+            .apply_verified_batch(0, &[(block_hash(1), filter_header(1))])
+            .unwrap();
+
+        assert_eq!(outcome, SyncOutcome::TipUnchanged);
+        assert_eq!(chain.tip_height(), 1);
+    }
+
+    #[test]
+    fn test_chain_filter_header_matches_manual_computation() {
+        let previous = filter_header(0);
+        let filter_hash = FilterHash::hash(&[7]);
+
+        let mut engine = sha256d::Hash::engine();
+        engine.input(filter_hash.as_ref());
+        engine.input(previous.as_ref());
+        let expected = FilterHeader::from_engine(engine);
+
+        assert_eq!(chain_filter_header(filter_hash, previous), expected);
+    }
+}
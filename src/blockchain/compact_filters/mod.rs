@@ -0,0 +1,75 @@
+// Magical Bitcoin Library
+// Written in 2020 by
+//     Alekos Filini <alekos.filini@gmail.com>
+//
+// Copyright (c) 2020 Magical Bitcoin
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Compact Filters (BIP157/BIP158) based blockchain backend
+
+pub(crate) mod download;
+pub(crate) mod filter_chain;
+pub(crate) mod peer;
+pub(crate) mod pool;
+
+pub use self::download::download_block_range;
+pub use self::filter_chain::{sync_cf_headers, ChainEntry, FilterHeaderChain, SyncOutcome};
+pub use self::peer::{AddressBook, AddressBookEntry, CompactFiltersPeer, InvPeer, Mempool, Peer};
+pub use self::pool::PeerPool;
+
+/// Errors that can happen during a sync with [`Peer`] or the compact filters backend
+#[derive(Debug)]
+pub enum CompactFiltersError {
+    /// A peer sent an invalid or unexpected message for the request that was made
+    InvalidResponse,
+    /// A peer's response couldn't be parsed correctly
+    DataCorruption,
+    /// A peer didn't answer a request before the timeout elapsed
+    Timeout,
+    /// A peer truthfully answered that it doesn't have the requested data; unlike the other
+    /// variants, this isn't a fault and shouldn't be held against the peer that returned it
+    NotFound,
+    /// No peers are available
+    NoPeers,
+    /// Wrapper for [`std::io::Error`]
+    Io(std::io::Error),
+    /// Wrapper for [`std::time::SystemTimeError`]
+    Time(std::time::SystemTimeError),
+}
+
+impl std::fmt::Display for CompactFiltersError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for CompactFiltersError {}
+
+impl From<std::io::Error> for CompactFiltersError {
+    fn from(err: std::io::Error) -> Self {
+        CompactFiltersError::Io(err)
+    }
+}
+
+impl From<std::time::SystemTimeError> for CompactFiltersError {
+    fn from(err: std::time::SystemTimeError) -> Self {
+        CompactFiltersError::Time(err)
+    }
+}
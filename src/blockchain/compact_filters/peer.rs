@@ -22,19 +22,21 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
-use std::collections::HashMap;
-use std::net::{TcpStream, ToSocketAddrs};
-use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::collections::{HashMap, HashSet};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{mpsc, Arc, Condvar, Mutex, RwLock};
 use std::thread;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use socks::{Socks5Stream, ToTargetAddr};
 
+use rand::seq::SliceRandom;
 use rand::{thread_rng, Rng};
 
 use bitcoin::consensus::Encodable;
 use bitcoin::hash_types::BlockHash;
 use bitcoin::hashes::Hash;
+use bitcoin::network::address::{AddrV2, AddrV2Message};
 use bitcoin::network::constants::ServiceFlags;
 use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
 use bitcoin::network::message_blockdata::*;
@@ -42,7 +44,7 @@ use bitcoin::network::message_filter::*;
 use bitcoin::network::message_network::VersionMessage;
 use bitcoin::network::stream_reader::StreamReader;
 use bitcoin::network::Address;
-use bitcoin::{Block, Network, Transaction, Txid};
+use bitcoin::{Block, Network, Script, Transaction, Txid};
 
 use super::CompactFiltersError;
 
@@ -50,22 +52,169 @@ type ResponsesMap = HashMap<&'static str, Arc<(Mutex<Vec<NetworkMessage>>, Condv
 
 pub(crate) const TIMEOUT_SECS: u64 = 30;
 
+/// Maximum number of candidate peers dialed concurrently by [`Peer::discover`]
+const DISCOVERY_CONCURRENCY: usize = 8;
+
+/// How long [`Peer::discover`] waits for a single candidate's TCP handshake before giving up on
+/// it and moving on to the next one
+const DISCOVERY_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long [`Peer::from_stream`] waits for the peer to complete the `version`/`verack`
+/// handshake before giving up; used by [`connect`](Peer::connect) and
+/// [`connect_proxy`](Peer::connect_proxy), which have no TCP-level deadline of their own to reuse
+/// for this
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default maximum number of transactions kept by a [`Mempool`] created with [`Mempool::default`]
+const DEFAULT_MEMPOOL_CAPACITY: usize = 10_000;
+
+/// A transaction held by a [`Mempool`], together with the bookkeeping used to decide what to
+/// evict once the mempool is over capacity
+#[derive(Debug, Clone)]
+struct MempoolEntry {
+    tx: Transaction,
+    /// Fee rate in sat/vbyte, estimated from other transactions already held in the mempool;
+    /// `None` if one or more inputs spend an output we don't have a local copy of, which is
+    /// treated as the lowest priority when deciding what to evict
+    feerate: Option<f32>,
+    /// Unix timestamp of when this entry was inserted
+    inserted_at: u64,
+}
+
 /// Container for unconfirmed, but valid Bitcoin transactions
 ///
 /// It is normally shared between [`Peer`]s with the use of [`Arc`], so that transactions are not
 /// duplicated in memory.
-#[derive(Debug, Default)]
+///
+/// To keep memory use bounded on a busy network, the mempool has a maximum [`capacity`]: once
+/// full, the lowest-feerate, oldest transactions are evicted to make room for new ones. Callers
+/// can also restrict what's kept at all with [`set_relevant_scripts`], so that only transactions
+/// with an output paying to one of a set of watched script pubkeys (for instance, derived from a
+/// wallet's descriptors) are retained. Inputs are not examined, so a transaction that only spends
+/// from a watched script is not retained.
+///
+/// [`capacity`]: Mempool::new
+/// [`set_relevant_scripts`]: Mempool::set_relevant_scripts
+#[derive(Debug)]
 pub struct Mempool {
-    txs: RwLock<HashMap<Txid, Transaction>>,
+    txs: RwLock<HashMap<Txid, MempoolEntry>>,
+    capacity: usize,
+    relevant_scripts: RwLock<Option<HashSet<Script>>>,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Mempool::new(DEFAULT_MEMPOOL_CAPACITY)
+    }
 }
 
 impl Mempool {
-    /// Add a transaction to the mempool
+    /// Create an empty mempool that holds at most `capacity` transactions
+    pub fn new(capacity: usize) -> Self {
+        Mempool {
+            txs: RwLock::new(HashMap::new()),
+            capacity,
+            relevant_scripts: RwLock::new(None),
+        }
+    }
+
+    /// Restrict the mempool to only retain transactions that pay to one of `scripts`
+    ///
+    /// Pass `None` to go back to retaining every transaction relayed to us, which is also the
+    /// default for a freshly created mempool.
+    pub fn set_relevant_scripts(&self, scripts: Option<HashSet<Script>>) {
+        *self.relevant_scripts.write().unwrap() = scripts;
+    }
+
+    fn is_relevant(&self, tx: &Transaction) -> bool {
+        match &*self.relevant_scripts.read().unwrap() {
+            None => true,
+            Some(scripts) => tx
+                .output
+                .iter()
+                .any(|output| scripts.contains(&output.script_pubkey)),
+        }
+    }
+
+    /// Estimate the fee rate of `tx` in sat/vbyte using only transactions already in `txs` as a
+    /// source of input values; returns `None` if any input spends an output we don't hold
+    fn estimate_feerate(txs: &HashMap<Txid, MempoolEntry>, tx: &Transaction) -> Option<f32> {
+        let input_value = tx
+            .input
+            .iter()
+            .map(|input| {
+                txs.get(&input.previous_output.txid)?
+                    .tx
+                    .output
+                    .get(input.previous_output.vout as usize)
+                    .map(|output| output.value)
+            })
+            .collect::<Option<Vec<_>>>()?
+            .into_iter()
+            .sum::<u64>();
+        let output_value = tx.output.iter().map(|output| output.value).sum::<u64>();
+        let fee = input_value.checked_sub(output_value)?;
+
+        Some(fee as f32 / (tx.get_weight() as f32 / 4.0))
+    }
+
+    fn current_timestamp() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+
+    /// Evict the lowest-feerate, oldest transactions until `txs` is back within `capacity`
+    fn evict_over_capacity(txs: &mut HashMap<Txid, MempoolEntry>, capacity: usize) {
+        if txs.len() <= capacity {
+            return;
+        }
+
+        let mut by_priority: Vec<Txid> = txs.keys().copied().collect();
+        by_priority.sort_by(|a, b| {
+            let a = &txs[a];
+            let b = &txs[b];
+            a.feerate
+                .partial_cmp(&b.feerate)
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then(a.inserted_at.cmp(&b.inserted_at))
+        });
+
+        for txid in by_priority.into_iter().take(txs.len() - capacity) {
+            txs.remove(&txid);
+        }
+    }
+
+    /// Add a transaction to the mempool, unless none of its outputs pays to one of the scripts
+    /// configured with [`set_relevant_scripts`](Mempool::set_relevant_scripts)
     ///
     /// Note that this doesn't propagate the transaction to other
     /// peers. To do that, [`broadcast`](crate::blockchain::Blockchain::broadcast) should be used.
     pub fn add_tx(&self, tx: Transaction) {
-        self.txs.write().unwrap().insert(tx.txid(), tx);
+        if !self.is_relevant(&tx) {
+            return;
+        }
+
+        let mut txs = self.txs.write().unwrap();
+        let entry = MempoolEntry {
+            feerate: Self::estimate_feerate(&txs, &tx),
+            inserted_at: Self::current_timestamp(),
+            tx: tx.clone(),
+        };
+        txs.insert(tx.txid(), entry);
+
+        Self::evict_over_capacity(&mut txs, self.capacity);
+    }
+
+    /// Remove every transaction that's been in the mempool for longer than `max_age`
+    pub fn purge_expired(&self, max_age: Duration) {
+        let now = Self::current_timestamp();
+        let max_age = max_age.as_secs();
+        self.txs
+            .write()
+            .unwrap()
+            .retain(|_, entry| now.saturating_sub(entry.inserted_at) <= max_age);
     }
 
     /// Look-up a transaction in the mempool given an [`Inventory`] request
@@ -75,7 +224,7 @@ impl Mempool {
             Inventory::Transaction(txid) => *txid,
             Inventory::WitnessTransaction(wtxid) => Txid::from_inner(wtxid.into_inner()),
         };
-        self.txs.read().unwrap().get(&txid).cloned()
+        self.txs.read().unwrap().get(&txid).map(|entry| entry.tx.clone())
     }
 
     /// Return whether or not the mempool contains a transaction with a given txid
@@ -85,7 +234,89 @@ impl Mempool {
 
     /// Return the list of transactions contained in the mempool
     pub fn iter_txs(&self) -> Vec<Transaction> {
-        self.txs.read().unwrap().values().cloned().collect()
+        self.txs
+            .read()
+            .unwrap()
+            .values()
+            .map(|entry| entry.tx.clone())
+            .collect()
+    }
+}
+
+/// A single entry in an [`AddressBook`], gossiped by a peer via `addr`/`addrv2`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressBookEntry {
+    /// Services advertised for this address
+    pub services: ServiceFlags,
+    /// Unix timestamp of the last time this address was seen, as reported by the peer
+    pub last_seen: u32,
+}
+
+/// A shared, de-duplicated collection of peer addresses learned via `addr`/`addrv2` gossip
+///
+/// It is normally shared between [`Peer`]s with the use of [`Arc`], so that discovery and the
+/// [`PeerPool`](super::pool::PeerPool) can top up connections from gossip instead of re-querying
+/// the DNS seeds used by [`Peer::discover`].
+#[derive(Debug, Default)]
+pub struct AddressBook {
+    addresses: RwLock<HashMap<SocketAddr, AddressBookEntry>>,
+}
+
+impl AddressBook {
+    fn insert(&self, address: SocketAddr, services: ServiceFlags, last_seen: u32) {
+        let mut addresses = self.addresses.write().unwrap();
+        let is_fresher = addresses
+            .get(&address)
+            .map_or(true, |existing| last_seen > existing.last_seen);
+        if is_fresher {
+            addresses.insert(address, AddressBookEntry { services, last_seen });
+        }
+    }
+
+    /// Return every known address whose advertised services include `required`
+    pub fn addresses_with_services(&self, required: ServiceFlags) -> Vec<SocketAddr> {
+        self.addresses
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, entry)| entry.services.has(required))
+            .map(|(address, _)| *address)
+            .collect()
+    }
+
+    /// Return the number of addresses currently stored
+    pub fn len(&self) -> usize {
+        self.addresses.read().unwrap().len()
+    }
+
+    /// Return whether the address book is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Hard-coded DNS seeds used by [`Peer::discover`] to bootstrap the initial set of candidates,
+/// one list per [`Network`]
+fn dns_seeds(network: Network) -> &'static [&'static str] {
+    match network {
+        Network::Bitcoin => &[
+            "seed.bitcoin.sipa.be",
+            "dnsseed.bluematt.me",
+            "dnsseed.bitcoin.dashjr.org",
+            "seed.bitcoinstats.com",
+            "seed.bitcoin.jonasschnelli.ch",
+            "seed.btc.petertodd.org",
+            "seed.bitcoin.sprovoost.nl",
+            "dnsseed.emzy.de",
+        ],
+        Network::Testnet => &[
+            "testnet-seed.bitcoin.jonasschnelli.ch",
+            "seed.tbtc.petertodd.org",
+            "seed.testnet.bitcoin.sprovoost.nl",
+            "testnet-seed.bluematt.me",
+        ],
+        Network::Signet => &["seed.signet.bitcoin.sprovoost.nl"],
+        Network::Regtest => &[],
     }
 }
 
@@ -99,6 +330,7 @@ pub struct Peer {
     connected: Arc<RwLock<bool>>,
 
     mempool: Arc<Mempool>,
+    address_book: Arc<AddressBook>,
 
     version: VersionMessage,
     network: Network,
@@ -112,11 +344,34 @@ impl Peer {
     pub fn connect<A: ToSocketAddrs>(
         address: A,
         mempool: Arc<Mempool>,
+        address_book: Arc<AddressBook>,
         network: Network,
     ) -> Result<Self, CompactFiltersError> {
         let stream = TcpStream::connect(address)?;
 
-        Peer::from_stream(stream, mempool, network)
+        Peer::from_stream(stream, mempool, address_book, network, HANDSHAKE_TIMEOUT)
+    }
+
+    /// Connect to a peer over a plaintext TCP connection, giving up after `timeout` if the TCP
+    /// handshake itself doesn't complete
+    ///
+    /// Unlike [`connect`](Peer::connect), this only accepts a single already-resolved
+    /// [`SocketAddr`], since [`TcpStream::connect_timeout`] doesn't accept anything that resolves
+    /// to more than one candidate. It's used by [`discover`](Peer::discover) and
+    /// [`PeerPool::replenish`](super::pool::PeerPool::replenish), where a black-holed candidate
+    /// shouldn't be able to stall the OS's (often minutes-long) default TCP connect timeout.
+    /// `timeout` also bounds the subsequent version handshake, so a candidate that completes the
+    /// TCP connection but never speaks Bitcoin can't hang the caller either.
+    pub(crate) fn connect_timeout(
+        address: SocketAddr,
+        mempool: Arc<Mempool>,
+        address_book: Arc<AddressBook>,
+        network: Network,
+        timeout: Duration,
+    ) -> Result<Self, CompactFiltersError> {
+        let stream = TcpStream::connect_timeout(&address, timeout)?;
+
+        Peer::from_stream(stream, mempool, address_book, network, timeout)
     }
 
     /// Connect to a peer through a SOCKS5 proxy, optionally by using some credentials, specified
@@ -129,6 +384,7 @@ impl Peer {
         proxy: P,
         credentials: Option<(&str, &str)>,
         mempool: Arc<Mempool>,
+        address_book: Arc<AddressBook>,
         network: Network,
     ) -> Result<Self, CompactFiltersError> {
         let socks_stream = if let Some((username, password)) = credentials {
@@ -137,14 +393,123 @@ impl Peer {
             Socks5Stream::connect(proxy, target)?
         };
 
-        Peer::from_stream(socks_stream.into_inner(), mempool, network)
+        Peer::from_stream(
+            socks_stream.into_inner(),
+            mempool,
+            address_book,
+            network,
+            HANDSHAKE_TIMEOUT,
+        )
+    }
+
+    /// Discover peers able to serve compact filters
+    ///
+    /// This resolves the hard-coded DNS seeds for `network` into a list of candidate addresses,
+    /// then dials them and completes the version handshake concurrently, keeping only the peers
+    /// whose advertised [`VersionMessage::services`] include both [`ServiceFlags::WITNESS`] and
+    /// [`ServiceFlags::COMPACT_FILTERS`]. Fresh candidates keep being dialed until `count`
+    /// compatible peers are connected or the seed list is exhausted.
+    pub fn discover(
+        network: Network,
+        mempool: Arc<Mempool>,
+        address_book: Arc<AddressBook>,
+        proxy: Option<SocketAddr>,
+        count: usize,
+    ) -> Result<Vec<Peer>, CompactFiltersError> {
+        let mut candidates = Self::resolve_dns_seeds(network)?;
+        if candidates.is_empty() {
+            return Err(CompactFiltersError::NoPeers);
+        }
+        candidates.shuffle(&mut thread_rng());
+
+        let required_services = ServiceFlags::WITNESS | ServiceFlags::COMPACT_FILTERS;
+        let mut candidates = candidates.into_iter();
+        let mut peers = Vec::new();
+
+        while peers.len() < count {
+            let missing = count - peers.len();
+            let batch: Vec<_> = candidates
+                .by_ref()
+                .take(DISCOVERY_CONCURRENCY.min(missing))
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+
+            let (sender, receiver) = mpsc::channel();
+            for candidate in batch {
+                let sender = sender.clone();
+                let mempool = Arc::clone(&mempool);
+                let address_book = Arc::clone(&address_book);
+                thread::spawn(move || {
+                    let result = match proxy {
+                        Some(proxy) => Peer::connect_proxy(
+                            candidate,
+                            proxy,
+                            None,
+                            mempool,
+                            address_book,
+                            network,
+                        ),
+                        None => Peer::connect_timeout(
+                            candidate,
+                            mempool,
+                            address_book,
+                            network,
+                            DISCOVERY_CONNECT_TIMEOUT,
+                        ),
+                    };
+                    let _ = sender.send(result);
+                });
+            }
+            // Drop our own sender so the receiver loop below terminates once every dialing
+            // thread has reported back.
+            drop(sender);
+
+            for result in receiver {
+                if let Ok(peer) = result {
+                    if peer.get_version().services.has(required_services) {
+                        peers.push(peer);
+                    }
+                }
+            }
+        }
+
+        Ok(peers)
     }
 
-    /// Create a [`Peer`] from an already connected TcpStream
+    /// Resolve the hard-coded DNS seeds for `network` into a flat list of candidate addresses
+    fn resolve_dns_seeds(network: Network) -> Result<Vec<SocketAddr>, CompactFiltersError> {
+        let port = Self::default_port(network);
+        let mut candidates = Vec::new();
+        for seed in dns_seeds(network) {
+            match (*seed, port).to_socket_addrs() {
+                Ok(addrs) => candidates.extend(addrs),
+                Err(e) => log::debug!("DNS seed {} failed to resolve: {:?}", seed, e),
+            }
+        }
+
+        Ok(candidates)
+    }
+
+    /// Return the default P2P port used on `network`
+    fn default_port(network: Network) -> u16 {
+        match network {
+            Network::Bitcoin => 8333,
+            Network::Testnet => 18333,
+            Network::Signet => 38333,
+            Network::Regtest => 18444,
+        }
+    }
+
+    /// Create a [`Peer`] from an already connected TcpStream, giving up if the version handshake
+    /// doesn't complete within `handshake_timeout`
     fn from_stream(
         stream: TcpStream,
         mempool: Arc<Mempool>,
+        address_book: Arc<AddressBook>,
         network: Network,
+        handshake_timeout: Duration,
     ) -> Result<Self, CompactFiltersError> {
         let writer = Arc::new(Mutex::new(stream.try_clone()?));
         let responses: Arc<RwLock<ResponsesMap>> = Arc::new(RwLock::new(HashMap::new()));
@@ -155,6 +520,7 @@ impl Peer {
         let reader_thread_responses = Arc::clone(&responses);
         let reader_thread_writer = Arc::clone(&writer);
         let reader_thread_mempool = Arc::clone(&mempool);
+        let reader_thread_address_book = Arc::clone(&address_book);
         let reader_thread_connected = Arc::clone(&connected);
         let reader_thread = thread::spawn(move || {
             Self::reader_thread(
@@ -163,6 +529,7 @@ impl Peer {
                 reader_thread_responses,
                 reader_thread_writer,
                 reader_thread_mempool,
+                reader_thread_address_book,
                 reader_thread_connected,
             )
         });
@@ -189,18 +556,20 @@ impl Peer {
                 0,
             )),
         )?;
-        let version = if let NetworkMessage::Version(version) =
-            Self::_recv(&responses, "version", None)?.unwrap()
+        let version = match Self::_recv(&responses, "version", Some(handshake_timeout))?
+            .ok_or(CompactFiltersError::Timeout)?
         {
-            version
-        } else {
-            return Err(CompactFiltersError::InvalidResponse);
+            NetworkMessage::Version(version) => version,
+            _ => return Err(CompactFiltersError::InvalidResponse),
         };
 
-        if let NetworkMessage::Verack = Self::_recv(&responses, "verack", None)?.unwrap() {
-            Self::_send(&mut locked_writer, network.magic(), NetworkMessage::Verack)?;
-        } else {
-            return Err(CompactFiltersError::InvalidResponse);
+        match Self::_recv(&responses, "verack", Some(handshake_timeout))?
+            .ok_or(CompactFiltersError::Timeout)?
+        {
+            NetworkMessage::Verack => {
+                Self::_send(&mut locked_writer, network.magic(), NetworkMessage::Verack)?;
+            }
+            _ => return Err(CompactFiltersError::InvalidResponse),
         }
 
         std::mem::drop(locked_writer);
@@ -211,6 +580,7 @@ impl Peer {
             responses,
             connected,
             mempool,
+            address_book,
             network,
             version,
         })
@@ -265,6 +635,47 @@ impl Peer {
         Ok(messages.pop())
     }
 
+    /// Pop a message off the `wait_for` queue without blocking, if one is already there
+    fn _try_recv(
+        responses: &Arc<RwLock<ResponsesMap>>,
+        wait_for: &'static str,
+    ) -> Option<NetworkMessage> {
+        let message_resp = {
+            let mut lock = responses.write().unwrap();
+            let message_resp = lock.entry(wait_for).or_default();
+            Arc::clone(&message_resp)
+        };
+
+        let (lock, _cvar) = &*message_resp;
+        lock.lock().unwrap().pop()
+    }
+
+    /// Wait for whichever of `wait_for`'s queues gets a message first, with a single shared
+    /// timeout. Returns the matching tag together with the message, or `None` once `timeout`
+    /// elapses without anything arriving on any of them.
+    fn _recv_any(
+        responses: &Arc<RwLock<ResponsesMap>>,
+        wait_for: &[&'static str],
+        timeout: Duration,
+    ) -> Result<Option<(&'static str, NetworkMessage)>, CompactFiltersError> {
+        const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+        let deadline = SystemTime::now() + timeout;
+        loop {
+            for &tag in wait_for {
+                if let Some(message) = Self::_try_recv(responses, tag) {
+                    return Ok(Some((tag, message)));
+                }
+            }
+
+            if SystemTime::now() >= deadline {
+                return Ok(None);
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
     /// Return the [`VersionMessage`] sent by the peer
     pub fn get_version(&self) -> &VersionMessage {
         &self.version
@@ -280,6 +691,11 @@ impl Peer {
         Arc::clone(&self.mempool)
     }
 
+    /// Return the address book populated by this peer's `addr`/`addrv2` gossip
+    pub fn get_address_book(&self) -> Arc<AddressBook> {
+        Arc::clone(&self.address_book)
+    }
+
     /// Return whether or not the peer is still connected
     pub fn is_connected(&self) -> bool {
         *self.connected.read().unwrap()
@@ -292,6 +708,7 @@ impl Peer {
         reader_thread_responses: Arc<RwLock<ResponsesMap>>,
         reader_thread_writer: Arc<Mutex<TcpStream>>,
         reader_thread_mempool: Arc<Mempool>,
+        reader_thread_address_book: Arc<AddressBook>,
         reader_thread_connected: Arc<RwLock<bool>>,
     ) {
         macro_rules! check_disconnect {
@@ -331,6 +748,28 @@ impl Peer {
                     continue;
                 }
                 NetworkMessage::Alert(_) => continue,
+                NetworkMessage::Addr(ref addr_list) => {
+                    for (timestamp, address) in addr_list {
+                        if let Ok(socket_addr) = address.socket_addr() {
+                            reader_thread_address_book.insert(
+                                socket_addr,
+                                address.services,
+                                *timestamp,
+                            );
+                        }
+                    }
+                }
+                NetworkMessage::AddrV2(ref addr_list) => {
+                    for entry in addr_list {
+                        if let Some(socket_addr) = addr_v2_to_socket_addr(entry) {
+                            reader_thread_address_book.insert(
+                                socket_addr,
+                                entry.services,
+                                entry.time,
+                            );
+                        }
+                    }
+                }
                 NetworkMessage::GetData(ref inv) => {
                     let (found, not_found): (Vec<_>, Vec<_>) = inv
                         .into_iter()
@@ -384,6 +823,50 @@ impl Peer {
     ) -> Result<Option<NetworkMessage>, CompactFiltersError> {
         Self::_recv(&self.responses, wait_for, timeout)
     }
+
+    /// Waits for whichever of several possible incoming Bitcoin messages arrives first
+    ///
+    /// Useful when a request can be answered by more than one command, e.g. a `getdata` for a
+    /// block can be answered with either `block` or `notfound`; racing both queues means a
+    /// `notfound` reply doesn't sit there unnoticed until the timeout for `block` elapses.
+    pub fn recv_first(
+        &self,
+        wait_for: &[&'static str],
+        timeout: Duration,
+    ) -> Result<Option<(&'static str, NetworkMessage)>, CompactFiltersError> {
+        Self::_recv_any(&self.responses, wait_for, timeout)
+    }
+
+    /// Ask this peer for its address book by sending a `getaddr` message
+    ///
+    /// The addresses in the response are recorded in the shared [`AddressBook`] (reachable via
+    /// [`get_address_book`](Peer::get_address_book)) by the reader thread as they come in; this
+    /// also returns just the addresses learned from this particular request.
+    pub fn ask_for_peers(&self) -> Result<Vec<SocketAddr>, CompactFiltersError> {
+        self.send(NetworkMessage::GetAddr)?;
+
+        let addr_list = match self.recv("addr", Some(Duration::from_secs(TIMEOUT_SECS)))? {
+            None => return Ok(vec![]),
+            Some(NetworkMessage::Addr(addr_list)) => addr_list,
+            _ => return Err(CompactFiltersError::InvalidResponse),
+        };
+
+        Ok(addr_list
+            .into_iter()
+            .filter_map(|(_, address)| address.socket_addr().ok())
+            .collect())
+    }
+}
+
+/// Convert an `addrv2` entry into a [`SocketAddr`], if its address variant maps to one
+///
+/// Tor, I2P and CJDNS addresses don't have a standard-library representation and are skipped.
+fn addr_v2_to_socket_addr(entry: &AddrV2Message) -> Option<SocketAddr> {
+    match entry.addr {
+        AddrV2::Ipv4(ip) => Some(SocketAddr::new(ip.into(), entry.port)),
+        AddrV2::Ipv6(ip) => Some(SocketAddr::new(ip.into(), entry.port)),
+        _ => None,
+    }
 }
 
 pub trait CompactFiltersPeer {
@@ -500,10 +983,13 @@ impl InvPeer for Peer {
             block_hash,
         )]))?;
 
-        match self.recv("block", Some(Duration::from_secs(TIMEOUT_SECS)))? {
-            None => Ok(None),
-            Some(NetworkMessage::Block(response)) => Ok(Some(response)),
-            _ => Err(CompactFiltersError::InvalidResponse),
+        // Race the "block" and "notfound" queues: a peer that simply doesn't have the block
+        // answers with `notfound`, which should be distinguishable from it not answering at all.
+        match self.recv_first(&["block", "notfound"], Duration::from_secs(TIMEOUT_SECS))? {
+            None => Err(CompactFiltersError::Timeout),
+            Some((_, NetworkMessage::Block(response))) => Ok(Some(response)),
+            Some((_, NetworkMessage::NotFound(_))) => Ok(None),
+            Some(_) => Err(CompactFiltersError::InvalidResponse),
         }
     }
 
@@ -548,3 +1034,169 @@ impl InvPeer for Peer {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin::{OutPoint, TxIn, TxOut, Witness};
+
+    /// Build a transaction paying `output_value` to `script_pubkey`, spending `inputs`
+    fn dummy_tx(inputs: Vec<OutPoint>, output_value: u64, script_pubkey: Script) -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: 0,
+            input: inputs
+                .into_iter()
+                .map(|previous_output| TxIn {
+                    previous_output,
+                    script_sig: Script::new(),
+                    sequence: 0xFFFFFFFF,
+                    witness: Witness::default(),
+                })
+                .collect(),
+            output: vec![TxOut {
+                value: output_value,
+                script_pubkey,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_evict_over_capacity_drops_none_feerate_first() {
+        let mempool = Mempool::new(2);
+
+        // No resolvable input: feerate is `None`, the worst priority.
+        let tx1 = dummy_tx(vec![OutPoint::null()], 1_000_000, Script::new());
+        mempool.add_tx(tx1.clone());
+
+        // Spends tx1: feerate is computable and positive.
+        let tx2 = dummy_tx(
+            vec![OutPoint::new(tx1.txid(), 0)],
+            999_000,
+            Script::new(),
+        );
+        mempool.add_tx(tx2.clone());
+
+        // Spends tx2 with a larger fee than tx2 paid: also computable.
+        let tx3 = dummy_tx(
+            vec![OutPoint::new(tx2.txid(), 0)],
+            997_000,
+            Script::new(),
+        );
+        mempool.add_tx(tx3.clone());
+
+        // Capacity is 2, so one entry had to go; the `None`-feerate one is always worst.
+        assert!(!mempool.has_tx(&tx1.txid()));
+        assert!(mempool.has_tx(&tx2.txid()));
+        assert!(mempool.has_tx(&tx3.txid()));
+    }
+
+    #[test]
+    fn test_purge_expired_removes_only_old_entries() {
+        let mempool = Mempool::new(10);
+
+        let fresh = dummy_tx(vec![OutPoint::null()], 1_000_000, Script::new());
+        let stale = dummy_tx(vec![OutPoint::null()], 2_000_000, Script::new());
+        mempool.add_tx(fresh.clone());
+        mempool.add_tx(stale.clone());
+
+        // Backdate `stale` as if it had been sitting in the mempool for a day already.
+        mempool
+            .txs
+            .write()
+            .unwrap()
+            .get_mut(&stale.txid())
+            .unwrap()
+            .inserted_at -= 24 * 60 * 60;
+
+        mempool.purge_expired(Duration::from_secs(60 * 60));
+
+        assert!(mempool.has_tx(&fresh.txid()));
+        assert!(!mempool.has_tx(&stale.txid()));
+    }
+
+    #[test]
+    fn test_set_relevant_scripts_filters_future_inserts() {
+        let mempool = Mempool::new(10);
+        let watched = Script::from(vec![0x51]);
+        let other = Script::from(vec![0x52]);
+
+        mempool.set_relevant_scripts(Some(vec![watched.clone()].into_iter().collect()));
+
+        let irrelevant = dummy_tx(vec![OutPoint::null()], 1_000, other);
+        mempool.add_tx(irrelevant.clone());
+        assert!(!mempool.has_tx(&irrelevant.txid()));
+
+        let relevant = dummy_tx(vec![OutPoint::null()], 1_000, watched);
+        mempool.add_tx(relevant.clone());
+        assert!(mempool.has_tx(&relevant.txid()));
+    }
+
+    #[test]
+    fn test_dns_seeds_regtest_is_empty() {
+        // There's no public DNS seed infrastructure for regtest, so `discover` shouldn't try to
+        // resolve anything for it.
+        assert!(dns_seeds(Network::Regtest).is_empty());
+    }
+
+    #[test]
+    fn test_dns_seeds_are_distinct_per_network() {
+        for network in [Network::Bitcoin, Network::Testnet, Network::Signet] {
+            assert!(!dns_seeds(network).is_empty());
+        }
+
+        assert_ne!(dns_seeds(Network::Bitcoin), dns_seeds(Network::Testnet));
+        assert_ne!(dns_seeds(Network::Bitcoin), dns_seeds(Network::Signet));
+        assert_ne!(dns_seeds(Network::Testnet), dns_seeds(Network::Signet));
+    }
+
+    #[test]
+    fn test_default_port_matches_network() {
+        assert_eq!(Peer::default_port(Network::Bitcoin), 8333);
+        assert_eq!(Peer::default_port(Network::Testnet), 18333);
+        assert_eq!(Peer::default_port(Network::Signet), 38333);
+        assert_eq!(Peer::default_port(Network::Regtest), 18444);
+    }
+
+    #[test]
+    fn test_address_book_insert_ignores_stale_update() {
+        let book = AddressBook::default();
+        let address: SocketAddr = "127.0.0.1:8333".parse().unwrap();
+
+        book.insert(address, ServiceFlags::WITNESS, 100);
+        // An older `last_seen` for an address already known shouldn't overwrite it, even if the
+        // advertised services differ.
+        book.insert(address, ServiceFlags::NETWORK, 50);
+
+        let entry = book.addresses.read().unwrap()[&address];
+        assert_eq!(entry.services, ServiceFlags::WITNESS);
+        assert_eq!(entry.last_seen, 100);
+    }
+
+    #[test]
+    fn test_address_book_insert_applies_fresher_update() {
+        let book = AddressBook::default();
+        let address: SocketAddr = "127.0.0.1:8333".parse().unwrap();
+
+        book.insert(address, ServiceFlags::WITNESS, 100);
+        book.insert(address, ServiceFlags::NETWORK, 200);
+
+        let entry = book.addresses.read().unwrap()[&address];
+        assert_eq!(entry.services, ServiceFlags::NETWORK);
+        assert_eq!(entry.last_seen, 200);
+    }
+
+    #[test]
+    fn test_addresses_with_services_filters_by_required_flags() {
+        let book = AddressBook::default();
+        let with_filters: SocketAddr = "127.0.0.1:8333".parse().unwrap();
+        let without_filters: SocketAddr = "127.0.0.2:8333".parse().unwrap();
+
+        let required = ServiceFlags::WITNESS | ServiceFlags::COMPACT_FILTERS;
+        book.insert(with_filters, required, 1);
+        book.insert(without_filters, ServiceFlags::WITNESS, 1);
+
+        assert_eq!(book.addresses_with_services(required), vec![with_filters]);
+    }
+}
@@ -0,0 +1,322 @@
+// Magical Bitcoin Library
+// Written in 2020 by
+//     Alekos Filini <alekos.filini@gmail.com>
+//
+// Copyright (c) 2020 Magical Bitcoin
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use bitcoin::hash_types::BlockHash;
+use bitcoin::network::message_filter::{CFHeaders, CFilter};
+use bitcoin::{Block, Network};
+
+use bitcoin::network::constants::ServiceFlags;
+
+use super::peer::{AddressBook, CompactFiltersPeer, InvPeer, Mempool, Peer};
+use super::CompactFiltersError;
+
+/// Score threshold below which a peer is considered misbehaving and gets dropped from the pool
+const MIN_SCORE: isize = -10;
+/// Score credited to a peer that answers a request correctly and within the timeout
+const SCORE_GOOD: isize = 1;
+/// Score debited from a peer that times out or answers with an invalid or corrupted message
+const SCORE_BAD: isize = -5;
+
+/// How long [`PeerPool::replenish`] waits for a single [`AddressBook`]-sourced candidate's TCP
+/// handshake before giving up on it, mirroring [`Peer::discover`]'s own timeout
+const REPLENISH_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A [`Peer`] together with the score [`PeerPool`] uses to decide when to drop it
+struct ScoredPeer {
+    peer: Peer,
+    score: AtomicIsize,
+}
+
+impl ScoredPeer {
+    fn new(peer: Peer) -> Self {
+        ScoredPeer {
+            peer,
+            score: AtomicIsize::new(0),
+        }
+    }
+
+    fn credit(&self) {
+        self.score.fetch_add(SCORE_GOOD, Ordering::SeqCst);
+    }
+
+    fn penalize(&self) {
+        self.score.fetch_add(SCORE_BAD, Ordering::SeqCst);
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.peer.is_connected() && is_score_healthy(self.score.load(Ordering::SeqCst))
+    }
+}
+
+/// Whether a peer with `score` is still above [`MIN_SCORE`], pulled out of
+/// [`ScoredPeer::is_healthy`] so the threshold itself can be tested without a connected [`Peer`]
+fn is_score_healthy(score: isize) -> bool {
+    score >= MIN_SCORE
+}
+
+/// A pool of [`Peer`]s sharing a single [`Mempool`], load-balanced in round-robin order
+///
+/// Requests made through [`get_cf_headers`](PeerPool::get_cf_headers),
+/// [`get_cf_filters`](PeerPool::get_cf_filters) and [`get_block`](PeerPool::get_block) are
+/// dispatched to the pool's peers in round-robin order, skipping disconnected or misbehaving
+/// ones. If the chosen peer times out or answers with an invalid or corrupted message, the
+/// request is transparently retried on the next healthy peer. Each peer keeps a score: a
+/// timeout, an [`InvalidResponse`](CompactFiltersError::InvalidResponse), or a
+/// [`DataCorruption`](CompactFiltersError::DataCorruption) decrements it, a well-formed, timely
+/// response credits it; once a peer's score drops below a threshold it is dropped from the pool
+/// and can be replaced, for instance with [`Peer::discover`].
+pub struct PeerPool {
+    peers: RwLock<Vec<Arc<ScoredPeer>>>,
+    mempool: Arc<Mempool>,
+    address_book: Arc<AddressBook>,
+    next: AtomicUsize,
+}
+
+impl PeerPool {
+    /// Create an empty pool whose peers will share `mempool` and `address_book`
+    pub fn new(mempool: Arc<Mempool>, address_book: Arc<AddressBook>) -> Self {
+        PeerPool {
+            peers: RwLock::new(Vec::new()),
+            mempool,
+            address_book,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Add a peer to the pool
+    pub fn add_peer(&self, peer: Peer) {
+        self.peers.write().unwrap().push(Arc::new(ScoredPeer::new(peer)));
+    }
+
+    /// Return the mempool shared by every peer in the pool
+    pub fn get_mempool(&self) -> Arc<Mempool> {
+        Arc::clone(&self.mempool)
+    }
+
+    /// Return the address book shared by every peer in the pool
+    pub fn get_address_book(&self) -> Arc<AddressBook> {
+        Arc::clone(&self.address_book)
+    }
+
+    /// Return the number of peers currently tracked by the pool, healthy or not
+    pub fn len(&self) -> usize {
+        self.peers.read().unwrap().len()
+    }
+
+    /// Return whether the pool has no peers
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drop every peer that disconnected or whose score fell below [`MIN_SCORE`]
+    fn evict_unhealthy(&self) {
+        self.peers.write().unwrap().retain(|p| p.is_healthy());
+    }
+
+    /// Bring the pool back up to `target` healthy peers
+    ///
+    /// Addresses gossiped to the pool's peers and stored in the shared [`AddressBook`] are tried
+    /// first, so the pool doesn't have to re-query the DNS seeds on every top-up; only once that
+    /// well runs dry does this fall back to [`Peer::discover`].
+    pub fn replenish(
+        &self,
+        network: Network,
+        proxy: Option<SocketAddr>,
+        target: usize,
+    ) -> Result<(), CompactFiltersError> {
+        self.evict_unhealthy();
+
+        let required_services = ServiceFlags::WITNESS | ServiceFlags::COMPACT_FILTERS;
+
+        for candidate in self.address_book.addresses_with_services(required_services) {
+            if self.len() >= target {
+                break;
+            }
+
+            let attempt = match proxy {
+                Some(proxy) => Peer::connect_proxy(
+                    candidate,
+                    proxy,
+                    None,
+                    self.get_mempool(),
+                    self.get_address_book(),
+                    network,
+                ),
+                // `candidate` comes from the gossiped, attacker-influenced `AddressBook`, so this
+                // is bounded the same way `Peer::discover` bounds its own candidates, instead of
+                // trusting the OS's default TCP connect timeout.
+                None => Peer::connect_timeout(
+                    candidate,
+                    self.get_mempool(),
+                    self.get_address_book(),
+                    network,
+                    REPLENISH_CONNECT_TIMEOUT,
+                ),
+            };
+
+            if let Ok(peer) = attempt {
+                if peer.get_version().services.has(required_services) {
+                    self.add_peer(peer);
+                }
+            }
+        }
+
+        let missing = target.saturating_sub(self.len());
+        if missing == 0 {
+            return Ok(());
+        }
+
+        for peer in Peer::discover(
+            network,
+            self.get_mempool(),
+            self.get_address_book(),
+            proxy,
+            missing,
+        )? {
+            self.add_peer(peer);
+        }
+
+        Ok(())
+    }
+
+    /// Dispatch `request` to a healthy peer in round-robin order, retrying on the next healthy
+    /// peer if it fails, until every healthy peer has been tried once
+    ///
+    /// [`CompactFiltersError::NotFound`] is treated as an honest, non-credited answer rather than
+    /// misbehavior: the peer isn't penalized for it, since truthfully not having some piece of
+    /// data isn't a fault. If every healthy peer returns it, `dispatch` itself returns
+    /// `Err(NotFound)` so the caller can tell "nobody currently known has this" apart from an
+    /// actual failure.
+    fn dispatch<T>(
+        &self,
+        mut request: impl FnMut(&Peer) -> Result<T, CompactFiltersError>,
+    ) -> Result<T, CompactFiltersError> {
+        self.evict_unhealthy();
+
+        let healthy = self.peers.read().unwrap().clone();
+        if healthy.is_empty() {
+            return Err(CompactFiltersError::NoPeers);
+        }
+
+        let start = self.next.fetch_add(1, Ordering::SeqCst) % healthy.len();
+        let mut last_err = CompactFiltersError::NoPeers;
+        for i in 0..healthy.len() {
+            let scored = &healthy[(start + i) % healthy.len()];
+            match request(&scored.peer) {
+                Ok(response) => {
+                    scored.credit();
+                    return Ok(response);
+                }
+                Err(CompactFiltersError::NotFound) => {
+                    // A real failure from another peer takes precedence over an honest `notfound`
+                    if matches!(last_err, CompactFiltersError::NoPeers) {
+                        last_err = CompactFiltersError::NotFound;
+                    }
+                }
+                Err(e) => {
+                    scored.penalize();
+                    last_err = e;
+                }
+            }
+        }
+
+        Err(last_err)
+    }
+
+    /// Fetch compact filter headers from the first healthy peer that answers
+    pub fn get_cf_headers(
+        &self,
+        filter_type: u8,
+        start_height: u32,
+        stop_hash: BlockHash,
+    ) -> Result<CFHeaders, CompactFiltersError> {
+        self.dispatch(|peer| peer.get_cf_headers(filter_type, start_height, stop_hash))
+    }
+
+    /// Fetch a compact filter from the first healthy peer that answers
+    pub fn get_cf_filters(
+        &self,
+        filter_type: u8,
+        start_height: u32,
+        stop_hash: BlockHash,
+    ) -> Result<CFilter, CompactFiltersError> {
+        self.dispatch(|peer| {
+            peer.get_cf_filters(filter_type, start_height, stop_hash)?;
+            peer.pop_cf_filter_resp()
+        })
+    }
+
+    /// Fetch a full block from the first healthy peer that answers
+    ///
+    /// Returns `Ok(None)` once every currently healthy peer has truthfully answered `notfound`;
+    /// that's a legitimate answer, not a fault, so it isn't held against any of them. A peer that
+    /// actually times out or sends an invalid response is still penalized and the request moved
+    /// on to the next one, same as [`get_cf_headers`](PeerPool::get_cf_headers) and
+    /// [`get_cf_filters`](PeerPool::get_cf_filters).
+    pub fn get_block(&self, block_hash: BlockHash) -> Result<Option<Block>, CompactFiltersError> {
+        let result = self.dispatch(|peer| match peer.get_block(block_hash)? {
+            Some(block) => Ok(Some(block)),
+            None => Err(CompactFiltersError::NotFound),
+        });
+
+        match result {
+            Err(CompactFiltersError::NotFound) => Ok(None),
+            other => other,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_score_healthy_threshold() {
+        assert!(is_score_healthy(0));
+        assert!(is_score_healthy(MIN_SCORE));
+        assert!(!is_score_healthy(MIN_SCORE - 1));
+    }
+
+    #[test]
+    fn test_two_penalties_are_not_enough_to_evict() {
+        // A single misbehaving/timed-out request should cost a peer a couple of `SCORE_BAD`
+        // penalties, at most, before another healthy peer in the pool can serve the same
+        // request (see `dispatch`) — not enough on its own to push every peer below `MIN_SCORE`
+        // and empty the pool, which is what an honest `notfound` reply used to do before it was
+        // split out into `CompactFiltersError::NotFound`.
+        let score = 2 * SCORE_BAD;
+        assert!(is_score_healthy(score));
+    }
+
+    #[test]
+    fn test_enough_penalties_evict() {
+        let score = 3 * SCORE_BAD;
+        assert!(!is_score_healthy(score));
+    }
+}